@@ -9,8 +9,33 @@ use serde_derive::Serialize;
 #[serde(rename_all = "snake_case")]
 pub struct Root {
     pub listen: String,
+    #[serde(default = "max_depth_default")]
+    pub max_depth: i32,
     pub fields: Vec<Field>,
     pub relations: Vec<Relation>,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    #[serde(default)]
+    pub cors: Option<Cors>,
+}
+
+fn max_depth_default() -> i32 {
+    10
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Auth {
+    /// secret used to verify the `Bearer` JWT sent by callers
+    pub jwt_secret: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Cors {
+    /// origins allowed to call the API; if empty, any origin is allowed
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -48,7 +73,7 @@ pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Root, anyhow::Error> {
             return Err(anyhow!("failed to read toml file: {}", why));
         }
     };
-    match toml::from_str(&*s) {
+    match toml::from_str(&s) {
         Ok(r) => Ok(r),
         Err(why) => Err(anyhow!("failed to decode toml file: {}", why)),
     }
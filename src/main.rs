@@ -3,12 +3,26 @@ use std::fmt::Debug;
 use std::hash::Hash;
 use std::sync::Arc;
 
+use std::convert::Infallible;
+use std::future::Future;
+use std::time::Duration;
+
 use anyhow::{anyhow, Error};
 use axum::{Json, Router, routing::get};
 use axum::extract::{Query, State};
-use futures_util::TryStreamExt;
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, Sse};
+use axum::response::Response;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use jsonwebtoken::{decode, DecodingKey, Validation};
 use serde_derive::{Deserialize, Serialize};
-use sqlx::{Column, Row, SqlitePool};
+use serde_json::Value;
+use sqlx::{AnyPool, Column, Row};
+use sqlx::any::AnyRow;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{debug, info};
 use tracing_subscriber::EnvFilter;
 
@@ -17,15 +31,85 @@ mod config;
 #[derive(Clone)]
 struct AppState {
     pub config: config::Root,
+    // kept for reference/introspection; traversal reads relations through `field_relations`
+    #[allow(dead_code)]
     pub connections: Vec<RelationReader>,
     pub field_relations: HashMap<String, Vec<RelationReader>>,
     pub fields: HashMap<String, config::Field>,
 }
 
+/// Which `sqlx::any` driver backs a relation, inferred from its `connect` URL scheme.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum DbKind {
+    Sqlite,
+    Postgres,
+    MySql,
+}
+
+impl DbKind {
+    fn from_connect_url(connect: &str) -> Result<DbKind, Error> {
+        match connect.split(':').next() {
+            Some("sqlite") => Ok(DbKind::Sqlite),
+            Some("postgres") | Some("postgresql") => Ok(DbKind::Postgres),
+            Some("mysql") => Ok(DbKind::MySql),
+            Some(scheme) => Err(anyhow!("unsupported database scheme `{}` in connect url", scheme)),
+            None => Err(anyhow!("connect url `{}` has no scheme", connect)),
+        }
+    }
+
+    /// Rewrite a `?`-style placeholder query into the syntax the detected
+    /// driver actually accepts (e.g. `$1`, `$2`, ... for Postgres).
+    ///
+    /// A plain `?` outside of a quoted literal is always treated as a bind
+    /// placeholder. Relations that need a literal `?` in unquoted SQL (e.g.
+    /// Postgres's JSONB `?`/`?|`/`?&` operators) must escape it as `??`,
+    /// following the same convention sqlx itself uses; `?` inside a quoted
+    /// string or identifier is left untouched either way.
+    fn rewrite_placeholders(&self, sql: &str) -> String {
+        let mut out = String::with_capacity(sql.len());
+        let mut n = 0;
+        let mut chars = sql.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' | '"' => {
+                    // copy the quoted literal/identifier verbatim
+                    // (including `''`/`""`-escaped quotes), so a `?`
+                    // inside it is never mistaken for a placeholder
+                    out.push(c);
+                    while let Some(inner) = chars.next() {
+                        out.push(inner);
+                        if inner == c && chars.peek() != Some(&c) {
+                            break;
+                        } else if inner == c {
+                            out.push(chars.next().unwrap());
+                        }
+                    }
+                }
+                '?' if chars.peek() == Some(&'?') => {
+                    // `??` escapes a literal `?`, e.g. for JSONB operators
+                    out.push('?');
+                    chars.next();
+                }
+                '?' => {
+                    n += 1;
+                    match self {
+                        // Sqlite and MySql already bind `?` positionally
+                        DbKind::Sqlite | DbKind::MySql => out.push('?'),
+                        DbKind::Postgres => out.push_str(&format!("${}", n)),
+                    }
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+}
+
 #[derive(Clone)]
 struct RelationReader {
     cfg: config::Relation,
-    db: SqlitePool,
+    kind: DbKind,
+    db: AnyPool,
 }
 
 impl RelationReader {
@@ -33,49 +117,135 @@ impl RelationReader {
         if cfg.fields.is_empty() {
             return Err(anyhow!("relation does not have any field"));
         }
-        match SqlitePool::connect(&cfg.connect).await {
+        let kind = DbKind::from_connect_url(&cfg.connect)?;
+        match AnyPool::connect(&cfg.connect).await {
             Ok(p) => Ok(RelationReader {
                 cfg: cfg.clone(),
+                kind,
                 db: p,
             }),
             Err(why) => {
-                Err(anyhow!("failed to connect to sqlite database `{}` for relation {}: {}",
-                    &cfg.connect, &cfg.name, why))
+                Err(anyhow!("failed to connect to {:?} database `{}` for relation {}: {} (is the driver compiled in?)",
+                    kind, &cfg.connect, &cfg.name, why))
             }
         }
     }
 }
 
 impl RelationReader {
-    pub async fn query(&mut self, field: &String, value: &String) -> Result<HashMap<String, String>, Error> {
+    /// `value` is bound as-is against `field = ?`, so it must carry the same
+    /// type the source relation originally reported it as (see
+    /// `bind_value`); re-stringifying a numeric or boolean value here would
+    /// make the bind mismatch a native `INTEGER`/`BOOL` column on Postgres.
+    pub async fn query(&mut self, field: &String, value: &Value) -> Result<HashMap<String, Value>, Error> {
         let fields = self.cfg.fields.iter().map(|r| {
-            return format!("({}) as {}", r.query, r.id);
+            format!("({}) as {}", r.query, r.id)
         }).reduce(|acc, s| acc + "," + &*s)
             .expect("relation does not have fields");
         let sql = format!(
             "select {fields} from {table_name} where {field} = ?",
             table_name = &self.cfg.table_name,
         );
+        let sql = self.kind.rewrite_placeholders(&sql);
         debug!("SQL: {}", sql);
-        let mut rows = sqlx::query(&*sql).bind(value).fetch(&self.db);
-        let mut rr: HashMap<String, String> = HashMap::new();
+        let query = bind_value(sqlx::query(sqlx::AssertSqlSafe(sql)), value);
+        let mut rows = query.fetch(&self.db);
+        let mut rr: HashMap<String, Value> = HashMap::new();
         while let Some(row) = rows.try_next().await? {
             for c in row.columns() {
                 let name = String::from(c.name());
-                let value: String = match row.try_get(&*name) {
-                    Ok(v) => v,
-                    Err(why) => {
-                        return Err(anyhow!("failed to get field `{}` when querying relation {}: {}",
-                        field, self.cfg.name, why));
-                    }
-                };
-                rr.insert(name, value);
+                rr.insert(name.clone(), extract_column(&row, &name));
             }
         }
         Ok(rr)
     }
 }
 
+/// Bind a JSON value to a `?`/`$n` placeholder using the native type it was
+/// originally read as (see `extract_column`), rather than collapsing it to a
+/// string first, so a join field that's a native `INTEGER`/`BOOL` column on
+/// Postgres still gets a parameter of the type it expects.
+fn bind_value<'q>(
+    query: sqlx::query::Query<'q, sqlx::Any, sqlx::any::AnyArguments>,
+    value: &'q Value,
+) -> sqlx::query::Query<'q, sqlx::Any, sqlx::any::AnyArguments> {
+    match value {
+        Value::Null => query.bind(Option::<String>::None),
+        Value::Bool(b) => query.bind(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => query.bind(i),
+            None => query.bind(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => query.bind(s.as_str()),
+        other => query.bind(other.to_string()),
+    }
+}
+
+/// Attempt the same typed coercion `extract_column` applies to database
+/// columns on a plain string seed value (from a query param or JSON body),
+/// so a numeric/bool primary key still binds with its native type on the
+/// very first hop instead of being mangled into a quoted string that a
+/// Postgres/MySQL `INTEGER`/`BOOL` column can't compare against.
+fn parse_seed_value(s: &str) -> Value {
+    if let Ok(v) = s.parse::<i64>() {
+        return Value::from(v);
+    }
+    if let Ok(v) = s.parse::<f64>() {
+        return Value::from(v);
+    }
+    if let Ok(v) = s.parse::<bool>() {
+        return Value::from(v);
+    }
+    Value::String(s.to_string())
+}
+
+/// Try each supported column type in turn, falling back to JSON `null` so
+/// that relations whose `query` expression yields an INTEGER, REAL, BLOB or
+/// NULL don't fail the whole traversal.
+fn extract_column(row: &AnyRow, name: &str) -> Value {
+    if let Ok(v) = row.try_get::<i64, _>(name) {
+        return Value::from(v);
+    }
+    if let Ok(v) = row.try_get::<f64, _>(name) {
+        return Value::from(v);
+    }
+    if let Ok(v) = row.try_get::<bool, _>(name) {
+        return Value::from(v);
+    }
+    if let Ok(v) = row.try_get::<String, _>(name) {
+        return Value::from(v);
+    }
+    // a real SQL NULL looks the same as a BLOB or other unsupported type
+    // once mapped to `Value::Null`; log so the latter isn't silently lost
+    debug!("column `{}` is NULL or an unsupported type (e.g. BLOB); mapping to JSON null", name);
+    Value::Null
+}
+
+/// A field value as used to key and bind a traversal node. `Null` is kept
+/// apart from `Known(String::new())` so a real SQL NULL can never collide
+/// with a real empty string in the same `distinct` field; unlike a real
+/// value, it also can't be bound as a `= ?` query parameter, so it never
+/// itself becomes a seed for a further hop.
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
+enum NodeValue {
+    Known(String),
+    Null,
+}
+
+impl NodeValue {
+    /// Render a typed field value back into the representation the
+    /// traversal uses internally to key and bind further queries, so
+    /// numeric IDs join correctly without being mangled into quoted JSON
+    /// strings.
+    fn from_value(value: &Value) -> NodeValue {
+        match value {
+            Value::String(s) => NodeValue::Known(s.clone()),
+            Value::Null => NodeValue::Null,
+            other => NodeValue::Known(other.to_string()),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -83,6 +253,8 @@ async fn main() {
         .init();
     debug!("debug log is enabled");
 
+    sqlx::any::install_default_drivers();
+
     let cfg = config::read_file("config.toml")
         .expect("error loading config file");
 
@@ -132,8 +304,13 @@ async fn main() {
     });
 
     // build our application with a single route
+    let cors = build_cors_layer(state.config.cors.as_ref());
+
     let app = Router::new()
-        .route("/query", get(query))
+        .route("/query", get(query).post(query_batch))
+        .route("/query/stream", get(query_stream))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_auth))
+        .layer(cors)
         .with_state(state);
 
     // run our app with hyper, listening globally on port 3000
@@ -149,7 +326,21 @@ async fn main() {
 struct RelationFieldValue {
     relation: String,
     field: String,
-    value: String,
+    value: NodeValue,
+}
+
+/// A single newly-discovered `(relation, field, value)` edge, reported by
+/// `traverse` as soon as it's found.
+struct Discovery {
+    relation: String,
+    field: String,
+    value: Value,
+    depth: i32,
+    /// the chain of edges connecting this value back to one of the initial
+    /// known field values, in seed-to-node order; built incrementally as
+    /// `traverse` discovers each node, so it reflects true discovery order
+    /// rather than a later, non-deterministic reconstruction
+    path: Vec<PathStep>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -157,7 +348,20 @@ struct RelationFieldValue {
 pub struct QueryResponse {
     pub success: bool,
     pub message: String,
-    pub data: BTreeMap<String, Vec<String>>,
+    pub data: BTreeMap<String, Vec<Value>>,
+    /// For each discovered `(field, value)`, the chain of edges connecting it
+    /// back to one of the initial known field values. The first element of
+    /// the chain is the seed (`relation: None`); every following element is
+    /// the relation that was queried to reach it.
+    pub paths: BTreeMap<String, BTreeMap<String, Vec<PathStep>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PathStep {
+    pub relation: Option<String>,
+    pub field: String,
+    pub value: Value,
 }
 
 
@@ -165,66 +369,179 @@ async fn query(
     State(state): State<Arc<AppState>>,
     Query(args): Query<HashMap<String, String>>,
 ) -> Json<QueryResponse> {
-    // Input: known field values
-    // Output: all reachable field values
-    //
-    // Program:
-    //   populate unvisited field value set with separate initial conditions
-    //   for all (field, value) in unvisited field value set:
-    //     for all relation containing this field:
-    //       mark (relation, field, value) as visited
-    //       get all other field values from relation with field, for each (field2, value2),
-    //       add all unvisited combinations (relation, field2, value2) to the unvisited field value set
-    //       stop if the unvisited set is empty, or iteration count exceeds limit
+    let max_depth = resolve_max_depth(&state, &args);
+    let reserve_max_depth = max_depth_is_reserved(&state);
+    let seed: HashMap<String, Vec<String>> = args.into_iter()
+        .filter(|(k, _)| !(reserve_max_depth && k == "max_depth"))
+        .map(|(k, v)| (k, vec![v]))
+        .collect();
+    Json(run_query(&state, seed, max_depth).await)
+}
+
+/// Request body for `POST /query`: one independent seed set per closure run.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct BatchQueryRequest {
+    seeds: Vec<HashMap<String, Vec<String>>>,
+}
+
+async fn query_batch(
+    State(state): State<Arc<AppState>>,
+    Query(args): Query<HashMap<String, String>>,
+    Json(body): Json<BatchQueryRequest>,
+) -> Json<Vec<QueryResponse>> {
+    let max_depth = resolve_max_depth(&state, &args);
+    let mut responses = Vec::with_capacity(body.seeds.len());
+    for seed in body.seeds {
+        responses.push(run_query(&state, seed, max_depth).await);
+    }
+    Json(responses)
+}
+
+/// Runs the transitive-closure BFS for a single seed set: `field -> known values`.
+/// Shared by the single-value GET endpoint and the multi-value, multi-seed POST endpoint.
+async fn run_query(
+    state: &Arc<AppState>,
+    known_field_values: HashMap<String, Vec<String>>,
+    max_depth: i32,
+) -> QueryResponse {
+    // inner maps are keyed by the value's canonical JSON form, so they both
+    // dedupe and order values without needing `Value` to implement `Hash`
+    let mut all_result: HashMap<String, BTreeMap<String, Value>> = HashMap::new();
+    let mut paths: HashMap<String, BTreeMap<String, Vec<PathStep>>> = HashMap::new();
+    let outcome = traverse(state, known_field_values, max_depth, |d: Discovery| {
+        let canonical = d.value.to_string();
+        paths.entry(d.field.clone())
+            .or_default()
+            .entry(canonical.clone())
+            .or_insert(d.path);
+        all_result.entry(d.field)
+            .or_default()
+            .entry(canonical)
+            .or_insert(d.value);
+        async {}
+    }).await;
+
+    let traversal = match outcome {
+        Ok(t) => t,
+        Err(message) => {
+            return QueryResponse {
+                success: false,
+                message,
+                data: Default::default(),
+                paths: Default::default(),
+            };
+        }
+    };
 
+    // use BTree maps to keep the response ordered
+    let all_result: BTreeMap<String, Vec<Value>> = all_result.into_iter().map(|(k, v)| {
+        (k, v.into_values().collect())
+    }).collect();
+    let paths: BTreeMap<String, BTreeMap<String, Vec<PathStep>>> = paths.into_iter().collect();
+    QueryResponse {
+        success: true,
+        message: String::from(if traversal.depth_limit_exceeded { "depth length limit exceeded" } else { "" }),
+        data: all_result,
+        paths,
+    }
+}
+
+/// Outcome of a completed (or depth-capped) `traverse` run.
+struct Traversal {
+    depth_limit_exceeded: bool,
+}
+
+/// Shared transitive-closure BFS used by both `run_query` (buffers the whole
+/// result) and `query_stream` (emits each discovery as it happens).
+///
+// Program:
+//   populate unvisited field value set with separate initial conditions
+//   for all (field, value) in unvisited field value set:
+//     for all relation containing this field:
+//       mark (relation, field, value) as visited
+//       get all other field values from relation with field, for each (field2, value2),
+//       add all unvisited combinations (relation, field2, value2) to the unvisited field value set
+//       stop if the unvisited set is empty, or iteration count exceeds limit
+///
+/// `on_discover` is invoked once for every node the traversal newly visits,
+/// in discovery order; callers use it to stream events or accumulate results.
+async fn traverse<F, Fut>(
+    state: &Arc<AppState>,
+    known_field_values: HashMap<String, Vec<String>>,
+    max_depth: i32,
+    mut on_discover: F,
+) -> Result<Traversal, String>
+where
+    F: FnMut(Discovery) -> Fut,
+    Fut: Future<Output=()>,
+{
     let mut unvisited = HashSet::new();
     let mut visited = HashSet::new();
-    let known_field_values = args;
+    // the chain of edges connecting each discovered node back to its seed,
+    // built incrementally as each node is first discovered (see below) so
+    // the path kept for a value never depends on a later, unordered pass
+    // over the finished traversal
+    let mut paths: HashMap<RelationFieldValue, Vec<PathStep>> = HashMap::new();
     // for the first query, we use even non-distinct fields as query condition
-    for (field, value) in known_field_values {
+    for (field, values) in known_field_values {
         let relations = match state.field_relations.get(&field) {
             Some(v) => v,
-            None => {
-                return Json(QueryResponse {
-                    success: false,
-                    message: format!("no relation has field `{}`", &field),
-                    data: Default::default(),
-                });
-            }
+            None => return Err(format!("no relation has field `{}`", &field)),
         };
-        for r in relations {
-            unvisited.insert(RelationFieldValue {
-                relation: r.cfg.name.clone(),
-                field: field.clone(),
-                value: value.clone(),
-            });
+        for value in values {
+            for r in relations {
+                let node = RelationFieldValue {
+                    relation: r.cfg.name.clone(),
+                    field: field.clone(),
+                    value: NodeValue::Known(value.clone()),
+                };
+                // seed values arrive as plain strings (from query params or a
+                // JSON body), so attempt the same numeric/bool coercion
+                // `extract_column` gives database columns before recording
+                // them, or a typed PK seed would fail its very first bind
+                paths.insert(node.clone(), vec![PathStep {
+                    relation: None,
+                    field: field.clone(),
+                    value: parse_seed_value(&value),
+                }]);
+                unvisited.insert(node);
+            }
         }
     }
-    // TODO make this configurable
-    const MAX_DEPTH: i32 = 10;
     let mut depth = 0;
     let mut depth_limit_exceeded = false;
-    let mut all_result: HashMap<_, HashSet<String>> = HashMap::new();
     while !unvisited.is_empty() {
-        if depth > MAX_DEPTH {
+        if depth > max_depth {
             depth_limit_exceeded = true;
             break;
         }
         // visit a cloned snapshot, updates will be reflected at once in the next loop round
         for task in unvisited.clone() {
             let (field, value) = (&task.field, &task.value);
+            // a NULL can't be bound as a `= ?` query parameter, so it never
+            // becomes the starting point of a further hop
+            let known_value = match value {
+                NodeValue::Known(s) => s,
+                NodeValue::Null => {
+                    unvisited.remove(&task);
+                    continue;
+                }
+            };
+            // bind the same typed value `data`/`paths` already carry for
+            // this node, not its stringified `NodeValue` form, so a native
+            // INTEGER/BOOL join column still gets a matching type
+            let typed_value = paths.get(&task)
+                .and_then(|chain| chain.last())
+                .expect("every node in unvisited has a recorded path")
+                .value
+                .clone();
             let mut relations = match state.field_relations.get(field) {
                 Some(v) => (*v).clone(),
-                None => {
-                    return Json(QueryResponse {
-                        success: false,
-                        message: format!("no relation has field `{}`", field),
-                        data: Default::default(),
-                    });
-                }
+                None => return Err(format!("no relation has field `{}`", field)),
             };
             for rel in relations.iter_mut() {
-                info!("visit: relation {}, field {}, value {}", rel.cfg.name, field, value);
+                info!("visit: relation {}, field {}, value {}", rel.cfg.name, field, known_value);
                 // ensure every (relation, field, value) is visited only once
                 if !visited.insert(RelationFieldValue {
                     relation: rel.cfg.name.clone(),
@@ -233,30 +550,44 @@ async fn query(
                 }) {
                     continue;
                 }
-                let result = match rel.query(field, value).await {
+                let result = match rel.query(field, &typed_value).await {
                     Ok(v) => v,
-                    Err(why) => {
-                        return Json(QueryResponse {
-                            success: false,
-                            message: format!("failed to query relation `{r}` with field `{f}`, value `{v}`: {why}",
-                                             r = &rel.cfg.name, f = field, v = value),
-                            data: Default::default(),
-                        });
-                    }
+                    Err(why) => return Err(format!(
+                        "failed to query relation `{r}` with field `{f}`, value `{v}`: {why}",
+                        r = &rel.cfg.name, f = field, v = known_value)),
                 };
                 for (field, value) in result {
-                    if let Some(set) = all_result.get_mut(&field) {
-                        set.insert(value.clone());
-                    } else {
-                        let mut s = HashSet::new();
-                        s.insert(value.clone());
-                        all_result.insert(field.clone(), s);
-                    }
                     let v = RelationFieldValue {
                         relation: rel.cfg.name.clone(),
                         field: field.clone(),
-                        value,
+                        value: NodeValue::from_value(&value),
                     };
+                    // only announce and record a path for the first (shortest,
+                    // since this is a BFS) time a node is reached; `visited`
+                    // only gains an entry for `v` once it's dequeued as a
+                    // source task, so two source tasks in the *same* round
+                    // that both reach `v` would otherwise both pass this
+                    // check and double-announce it. `paths` is populated
+                    // exactly once per node (right below), so it's the
+                    // correct "already discovered" guard.
+                    if !paths.contains_key(&v) {
+                        let mut new_path = paths.get(&task)
+                            .expect("every visited task has a recorded path")
+                            .clone();
+                        new_path.push(PathStep {
+                            relation: Some(rel.cfg.name.clone()),
+                            field: field.clone(),
+                            value: value.clone(),
+                        });
+                        let path = paths.entry(v.clone()).or_insert_with(|| new_path).clone();
+                        on_discover(Discovery {
+                            relation: rel.cfg.name.clone(),
+                            field: field.clone(),
+                            value: value.clone(),
+                            depth,
+                            path,
+                        }).await;
+                    }
                     // skip non-distinct fields to prevent generating irrelevant results
                     if !state.fields.get(&field).expect("missing field info").distinct {
                         continue;
@@ -276,19 +607,142 @@ async fn query(
         }
         depth += 1;
     }
-    // use BTree map to keep the result ordered
-    let all_result: BTreeMap<String, Vec<String>> = all_result.iter().map(|(k, v)| {
-        let mut v: Vec<String> = v.iter().map(|v| v.clone()).collect();
-        v.sort();
-        (k.clone(), v)
-    }).collect();
-    Json(QueryResponse {
-        success: true,
-        message: String::from(if depth_limit_exceeded { "depth length limit exceeded" } else { "" }),
-        data: all_result,
-    })
+    Ok(Traversal { depth_limit_exceeded })
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct DiscoveredEvent {
+    relation: String,
+    field: String,
+    value: Value,
+    depth: i32,
 }
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct DoneEvent {
+    depth_limit_exceeded: bool,
+}
+
+/// Same traversal as `query`, but emits an SSE event for every newly
+/// discovered `(field, value)` as soon as it is found, instead of waiting
+/// for the whole transitive closure before responding.
+async fn query_stream(
+    State(state): State<Arc<AppState>>,
+    Query(args): Query<HashMap<String, String>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (tx, rx) = mpsc::channel::<Event>(32);
+    let max_depth = resolve_max_depth(&state, &args);
+    let reserve_max_depth = max_depth_is_reserved(&state);
+    let seed: HashMap<String, Vec<String>> = args.into_iter()
+        .filter(|(k, _)| !(reserve_max_depth && k == "max_depth"))
+        .map(|(k, v)| (k, vec![v]))
+        .collect();
+
+    tokio::spawn(async move {
+        let outcome = traverse(&state, seed, max_depth, |d: Discovery| {
+            let tx = tx.clone();
+            async move {
+                let event = Event::default()
+                    .event("discovered")
+                    .json_data(DiscoveredEvent {
+                        relation: d.relation,
+                        field: d.field,
+                        value: d.value,
+                        depth: d.depth,
+                    })
+                    .expect("failed to serialize discovered event");
+                let _ = tx.send(event).await;
+            }
+        }).await;
+
+        let event = match outcome {
+            Ok(t) => Event::default()
+                .event("done")
+                .json_data(DoneEvent { depth_limit_exceeded: t.depth_limit_exceeded })
+                .expect("failed to serialize done event"),
+            Err(message) => error_event(message),
+        };
+        let _ = tx.send(event).await;
+    });
+
+    Sse::new(ReceiverStream::new(rx).map(Ok::<_, Infallible>))
+        .keep_alive(axum::response::sse::KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+fn error_event(message: String) -> Event {
+    Event::default()
+        .event("error")
+        .data(message)
+}
+
+/// `max_depth` is only a reserved query-string key when no relation field is
+/// actually configured with that name; otherwise it must be usable as a seed
+/// like any other field.
+fn max_depth_is_reserved(state: &AppState) -> bool {
+    !state.fields.contains_key("max_depth")
+}
+
+/// Resolve the depth limit for a single request: the caller's `max_depth`
+/// query parameter, clamped to the configured ceiling, or the ceiling itself
+/// if no parameter (or a nonsensical one) was given, or if `max_depth` is
+/// itself a configured field name.
+fn resolve_max_depth(state: &AppState, args: &HashMap<String, String>) -> i32 {
+    if !max_depth_is_reserved(state) {
+        return state.config.max_depth;
+    }
+    match args.get("max_depth").and_then(|s| s.parse::<i32>().ok()) {
+        Some(requested) if requested >= 0 => requested.min(state.config.max_depth),
+        _ => state.config.max_depth,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    // never read directly; `jsonwebtoken::decode`'s default `Validation` checks it against now()
+    #[allow(dead_code)]
+    exp: usize,
+}
+
+/// Validates a `Bearer` JWT against `[auth].jwt_secret` before letting the
+/// request reach `query`/`query_batch`/`query_stream`. A no-op when no
+/// `[auth]` block is configured.
+async fn require_auth(
+    State(state): State<Arc<AppState>>,
+    req: axum::extract::Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(auth) = &state.config.auth else {
+        return Ok(next.run(req).await);
+    };
+    let token = req.headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    decode::<JwtClaims>(token, &DecodingKey::from_secret(auth.jwt_secret.as_bytes()), &Validation::default())
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    Ok(next.run(req).await)
+}
+
+/// Builds the CORS layer from the optional `[cors]` config block: a
+/// permissive layer if unconfigured, otherwise restricted to the listed
+/// `allowed_origins`.
+fn build_cors_layer(cfg: Option<&config::Cors>) -> CorsLayer {
+    let layer = CorsLayer::new()
+        .allow_methods([axum::http::Method::GET, axum::http::Method::POST])
+        .allow_headers([axum::http::header::AUTHORIZATION, axum::http::header::CONTENT_TYPE]);
+    match cfg {
+        Some(c) if !c.allowed_origins.is_empty() => {
+            let origins: Vec<HeaderValue> = c.allowed_origins.iter()
+                .map(|o| o.parse().expect("invalid origin in [cors].allowed_origins"))
+                .collect();
+            layer.allow_origin(AllowOrigin::list(origins))
+        }
+        _ => layer.allow_origin(tower_http::cors::Any),
+    }
+}
 
 fn get_fields(cfg: &config::Root) -> Result<HashSet<String>, anyhow::Error> {
     let mut s: HashSet<String> = HashSet::new();
@@ -298,4 +752,178 @@ fn get_fields(cfg: &config::Root) -> Result<HashSet<String>, anyhow::Error> {
         }
     }
     Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_placeholders_postgres_numbers_sequentially() {
+        let sql = DbKind::Postgres.rewrite_placeholders("select * from t where a = ? and b = ?");
+        assert_eq!(sql, "select * from t where a = $1 and b = $2");
+    }
+
+    #[test]
+    fn rewrite_placeholders_sqlite_and_mysql_pass_through() {
+        let sql = "select * from t where a = ? and b = ?";
+        assert_eq!(DbKind::Sqlite.rewrite_placeholders(sql), sql);
+        assert_eq!(DbKind::MySql.rewrite_placeholders(sql), sql);
+    }
+
+    #[test]
+    fn rewrite_placeholders_leaves_quoted_question_marks_alone() {
+        let sql = DbKind::Postgres.rewrite_placeholders("select '?' from t where a = ?");
+        assert_eq!(sql, "select '?' from t where a = $1");
+    }
+
+    #[test]
+    fn rewrite_placeholders_unescapes_doubled_question_marks_on_every_backend() {
+        let sql = "select data ?? 'key' from t where a = ?";
+        assert_eq!(DbKind::Postgres.rewrite_placeholders(sql), "select data ? 'key' from t where a = $1");
+        assert_eq!(DbKind::Sqlite.rewrite_placeholders(sql), "select data ? 'key' from t where a = ?");
+        assert_eq!(DbKind::MySql.rewrite_placeholders(sql), "select data ? 'key' from t where a = ?");
+    }
+
+    #[test]
+    fn parse_seed_value_prefers_numeric_and_bool_over_string() {
+        assert_eq!(parse_seed_value("42"), Value::from(42i64));
+        assert_eq!(parse_seed_value("3.5"), Value::from(3.5f64));
+        assert_eq!(parse_seed_value("true"), Value::from(true));
+        assert_eq!(parse_seed_value("abc"), Value::String("abc".to_string()));
+    }
+
+    async fn memory_pool() -> AnyPool {
+        sqlx::any::install_default_drivers();
+        AnyPool::connect("sqlite::memory:").await.expect("failed to open in-memory sqlite db")
+    }
+
+    #[tokio::test]
+    async fn extract_column_tries_types_in_fallback_order() {
+        let pool = memory_pool().await;
+        let row = sqlx::query("select 1 as i, 1.5 as f, 'x' as s, null as n")
+            .fetch_one(&pool).await.expect("query failed");
+        assert_eq!(extract_column(&row, "i"), Value::from(1i64));
+        assert_eq!(extract_column(&row, "f"), Value::from(1.5f64));
+        assert_eq!(extract_column(&row, "s"), Value::from("x"));
+        assert_eq!(extract_column(&row, "n"), Value::Null);
+    }
+
+    #[tokio::test]
+    async fn bind_value_round_trips_numbers_strings_and_null_through_their_native_type() {
+        let pool = memory_pool().await;
+        for (value, expected) in [
+            (Value::from(42i64), Value::from(42i64)),
+            (Value::from(1.5f64), Value::from(1.5f64)),
+            (Value::String("x".to_string()), Value::from("x")),
+            (Value::Null, Value::Null),
+        ] {
+            let query = bind_value(sqlx::query("select ? as v"), &value);
+            let row = query.fetch_one(&pool).await.expect("query failed");
+            assert_eq!(extract_column(&row, "v"), expected);
+        }
+    }
+
+    fn state_with_fields(field_ids: &[&str]) -> AppState {
+        let config = config::Root { max_depth: 5, ..Default::default() };
+        AppState {
+            config,
+            connections: Vec::new(),
+            field_relations: HashMap::new(),
+            fields: field_ids.iter().map(|id| (id.to_string(), config::Field::default())).collect(),
+        }
+    }
+
+    #[test]
+    fn max_depth_is_reserved_unless_a_field_claims_the_name() {
+        assert!(max_depth_is_reserved(&state_with_fields(&["id"])));
+        assert!(!max_depth_is_reserved(&state_with_fields(&["id", "max_depth"])));
+    }
+
+    #[test]
+    fn resolve_max_depth_clamps_to_the_configured_ceiling() {
+        let state = state_with_fields(&["id"]);
+        let args = HashMap::from([("max_depth".to_string(), "2".to_string())]);
+        assert_eq!(resolve_max_depth(&state, &args), 2);
+
+        let args = HashMap::from([("max_depth".to_string(), "100".to_string())]);
+        assert_eq!(resolve_max_depth(&state, &args), 5);
+    }
+
+    #[test]
+    fn resolve_max_depth_falls_back_to_the_ceiling_on_a_missing_or_bad_value() {
+        let state = state_with_fields(&["id"]);
+        assert_eq!(resolve_max_depth(&state, &HashMap::new()), 5);
+
+        let args = HashMap::from([("max_depth".to_string(), "-1".to_string())]);
+        assert_eq!(resolve_max_depth(&state, &args), 5);
+
+        let args = HashMap::from([("max_depth".to_string(), "not a number".to_string())]);
+        assert_eq!(resolve_max_depth(&state, &args), 5);
+    }
+
+    #[test]
+    fn resolve_max_depth_ignores_the_query_param_when_max_depth_is_a_field() {
+        let state = state_with_fields(&["id", "max_depth"]);
+        let args = HashMap::from([("max_depth".to_string(), "1".to_string())]);
+        assert_eq!(resolve_max_depth(&state, &args), 5);
+    }
+
+    // two seed values (`parent_id` 1 and 2) both join to the same
+    // `child_id` 100, so this relation/field/value is reachable from two
+    // different source tasks in the very same BFS round.
+    #[tokio::test]
+    async fn traverse_announces_a_converging_node_exactly_once() {
+        sqlx::any::install_default_drivers();
+        let relation_cfg = config::Relation {
+            name: "rel".to_string(),
+            connect: "sqlite::memory:".to_string(),
+            table_name: "t".to_string(),
+            fields: vec![
+                config::RelationField { id: "parent_id".to_string(), query: "parent_id".to_string() },
+                config::RelationField { id: "child_id".to_string(), query: "child_id".to_string() },
+            ],
+        };
+        // a plain `AnyPool::connect("sqlite::memory:")` would hand out a
+        // fresh, separate in-memory database per connection; pin the pool to
+        // a single connection so every query in this test sees the same db
+        let db = sqlx::any::AnyPoolOptions::new()
+            .max_connections(1)
+            .connect(&relation_cfg.connect).await
+            .expect("failed to open relation");
+        let reader = RelationReader { cfg: relation_cfg, kind: DbKind::Sqlite, db };
+        sqlx::query("create table t (parent_id integer, child_id integer)")
+            .execute(&reader.db).await.expect("failed to create table");
+        sqlx::query("insert into t (parent_id, child_id) values (1, 100), (2, 100)")
+            .execute(&reader.db).await.expect("failed to seed table");
+
+        let mut field_relations = HashMap::new();
+        field_relations.insert("parent_id".to_string(), vec![reader.clone()]);
+        field_relations.insert("child_id".to_string(), vec![reader]);
+        let state = Arc::new(AppState {
+            config: config::Root { max_depth: 5, ..Default::default() },
+            connections: Vec::new(),
+            field_relations,
+            fields: HashMap::from([
+                ("parent_id".to_string(), config::Field::default()),
+                ("child_id".to_string(), config::Field::default()),
+            ]),
+        });
+
+        let seed = HashMap::from([("parent_id".to_string(), vec!["1".to_string(), "2".to_string()])]);
+        let discoveries: Arc<tokio::sync::Mutex<Vec<(String, Value)>>> = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+        let outcome = traverse(&state, seed, 5, |d: Discovery| {
+            let discoveries = discoveries.clone();
+            async move {
+                discoveries.lock().await.push((d.field, d.value));
+            }
+        }).await.expect("traversal failed");
+        assert!(!outcome.depth_limit_exceeded);
+
+        let discoveries = discoveries.lock().await;
+        let child_hits = discoveries.iter()
+            .filter(|(field, value)| field == "child_id" && value.as_i64() == Some(100))
+            .count();
+        assert_eq!(child_hits, 1, "child_id=100 should be discovered exactly once, got {:?}", *discoveries);
+    }
 }
\ No newline at end of file